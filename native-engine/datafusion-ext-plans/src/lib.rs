@@ -17,12 +17,15 @@
 #![feature(offset_of)]
 #![feature(async_closure)]
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use datafusion::error::{DataFusionError, Result};
 use hdfs_native::Client;
 use hdfs_native_object_store::HdfsObjectStore;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
 
 // execution plan implementations
 pub mod agg_exec;
@@ -59,32 +62,33 @@ pub mod joins;
 mod shuffle;
 pub mod window;
 
-pub fn get_hdfs_object_store() -> Result<Arc<HdfsObjectStore>> {
-    static HDFS_OBJECT_STORE: OnceCell<Arc<HdfsObjectStore>> = OnceCell::new();
-    let config: HashMap<String, String> = HashMap::from([
-        (
-            "dfs.ha.namenodes.blaze-test".to_string(),
-            "nn1,nn2,nn3".to_string(),
-        ),
-        (
-            "dfs.namenode.rpc-address.blaze-test.nn1".to_string(),
-            "10.108.234.143:8020".to_string(),
-        ),
-        (
-            "dfs.namenode.rpc-address.blaze-test.nn2".to_string(),
-            "10.14.35.152:8020".to_string(),
-        ),
-        (
-            "dfs.namenode.rpc-address.blaze-test.nn3".to_string(),
-            "10.14.35.231:8020".to_string(),
-        ),
-    ]);
-    Ok(HDFS_OBJECT_STORE
-        .get_or_try_init(|| {
-            Ok::<_, DataFusionError>(Arc::new(HdfsObjectStore::new(
-                Client::new_with_config("hdfs://blaze-test", config.clone())
-                    .map_err(|e| DataFusionError::External(Box::new(e)))?,
-            )))
-        })?
-        .clone())
+/// caches one [`HdfsObjectStore`] per nameservice URI (e.g. `hdfs://prod-ns`),
+/// so a single executor can talk to more than one cluster during a job.
+static HDFS_OBJECT_STORES: Lazy<Mutex<HashMap<String, Arc<HdfsObjectStore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the object store for the given `nameservice`, creating and caching it
+/// on first use.
+///
+/// The HA namenode map and any other `dfs.*` keys come from `config` rather
+/// than being hardcoded. This function does not read the Hadoop
+/// `Configuration` itself; the caller supplies every key in `config`.
+/// Subsequent lookups for the same nameservice reuse the cached store and
+/// ignore `config`.
+pub fn get_hdfs_object_store(
+    nameservice: &str,
+    config: &HashMap<String, String>,
+) -> Result<Arc<HdfsObjectStore>> {
+    let url = format!("hdfs://{nameservice}");
+    let mut stores = HDFS_OBJECT_STORES.lock().unwrap();
+    if let Some(store) = stores.get(&url) {
+        return Ok(store.clone());
+    }
+
+    let store = Arc::new(HdfsObjectStore::new(
+        Client::new_with_config(&url, config.clone())
+            .map_err(|e| DataFusionError::External(Box::new(e)))?,
+    ));
+    stores.insert(url, store.clone());
+    Ok(store)
 }
@@ -0,0 +1,240 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A reusable batch partitioner shared by the shuffle writer execs.
+
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+use datafusion::arrow::array::{ArrayRef, UInt64Array};
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::{DataFusionError, Result};
+use datafusion::physical_plan::metrics::Time;
+use datafusion::physical_plan::Partitioning;
+use itertools::Itertools;
+
+use crate::shuffle::{evaluate_hashes, evaluate_partition_ids};
+
+/// Splits an input [`RecordBatch`] into one slice per output partition.
+///
+/// The hash path reuses a stable counting sort so rows keep their relative
+/// order within a partition, then materializes each slice with
+/// [`arrow::compute::take`]. Both `Hash` and `RoundRobin` partitioning are
+/// supported, and the time spent partitioning is recorded into the supplied
+/// metric. Keeping this logic in one place means a new partitioning scheme only
+/// has to be added here.
+pub struct BatchPartitioner {
+    partitioning: Partitioning,
+    num_partitions: usize,
+    /// round-robin cursor, advanced by each batch's row count.
+    round_robin_start: AtomicUsize,
+    partition_time: Time,
+}
+
+impl BatchPartitioner {
+    /// Creates a partitioner for `partitioning`, recording elapsed time into
+    /// `partition_time`.
+    pub fn try_new(partitioning: Partitioning, partition_time: Time) -> Result<Self> {
+        match partitioning {
+            Partitioning::Hash(..) | Partitioning::RoundRobinBatch(..) => {}
+            other => {
+                return Err(DataFusionError::NotImplemented(format!(
+                    "BatchPartitioner does not support {other:?}"
+                )))
+            }
+        }
+        let num_partitions = partitioning.partition_count();
+        Ok(Self {
+            partitioning,
+            num_partitions,
+            round_robin_start: AtomicUsize::new(0),
+            partition_time,
+        })
+    }
+
+    /// Partitions `batch`, yielding each non-empty `(partition_id, batch)` slice
+    /// in ascending partition order.
+    pub fn partition(
+        &self,
+        batch: &RecordBatch,
+    ) -> Result<std::vec::IntoIter<(usize, RecordBatch)>> {
+        let timer = self.partition_time.timer();
+        let partitioned = match &self.partitioning {
+            Partitioning::Hash(..) => self.partition_hash(batch)?,
+            Partitioning::RoundRobinBatch(..) => self.partition_round_robin(batch)?,
+            // try_new rejected every other partitioning.
+            _ => unreachable!(),
+        };
+        timer.done();
+        Ok(partitioned.into_iter())
+    }
+
+    fn partition_hash(&self, batch: &RecordBatch) -> Result<Vec<(usize, RecordBatch)>> {
+        let hashes = evaluate_hashes(&self.partitioning, batch)?;
+        let partition_ids = evaluate_partition_ids(&hashes, self.num_partitions);
+        self.partition_by_ids(batch, &partition_ids)
+    }
+
+    /// Slices `batch` by precomputed per-row `partition_ids` using a stable
+    /// counting sort, so rows keep their relative order within each partition.
+    fn partition_by_ids(
+        &self,
+        batch: &RecordBatch,
+        partition_ids: &[u32],
+    ) -> Result<Vec<(usize, RecordBatch)>> {
+        let num_output_partitions = self.num_partitions;
+
+        // count each partition size
+        let mut partition_counters = vec![0usize; num_output_partitions];
+        for &partition_id in partition_ids {
+            partition_counters[partition_id as usize] += 1
+        }
+
+        // accumulate partition counters into partition ends
+        let mut partition_ends = partition_counters;
+        let mut accum = 0;
+        partition_ends.iter_mut().for_each(|v| {
+            *v += accum;
+            accum = *v;
+        });
+
+        // calculate shuffled partition ids (stable counting sort)
+        let mut shuffled_partition_ids = vec![0u64; batch.num_rows()];
+        for (index, &partition_id) in partition_ids.iter().enumerate().rev() {
+            partition_ends[partition_id as usize] -= 1;
+            let end = partition_ends[partition_id as usize];
+            shuffled_partition_ids[end] = index as u64;
+        }
+
+        // after calculating, partition ends become partition starts
+        let mut partition_starts = partition_ends;
+        partition_starts.push(batch.num_rows());
+
+        partition_starts
+            .iter()
+            .tuple_windows()
+            .enumerate()
+            .filter(|(_, (start, end))| start < end)
+            .map(|(partition_id, (&start, &end))| {
+                let indices = UInt64Array::from_iter_values(
+                    shuffled_partition_ids[start..end].iter().copied(),
+                );
+                Ok((partition_id, take_batch(batch, &indices)?))
+            })
+            .collect()
+    }
+
+    fn partition_round_robin(
+        &self,
+        batch: &RecordBatch,
+    ) -> Result<Vec<(usize, RecordBatch)>> {
+        let num_output_partitions = self.num_partitions;
+        let num_rows = batch.num_rows();
+        let start = self.round_robin_start.fetch_add(num_rows, Relaxed);
+
+        // spread rows across partitions, continuing the cursor between batches.
+        let mut buckets = vec![vec![]; num_output_partitions];
+        for row in 0..num_rows {
+            buckets[(start + row) % num_output_partitions].push(row as u64);
+        }
+
+        buckets
+            .into_iter()
+            .enumerate()
+            .filter(|(_, rows)| !rows.is_empty())
+            .map(|(partition_id, rows)| {
+                let indices = UInt64Array::from_iter_values(rows);
+                Ok((partition_id, take_batch(batch, &indices)?))
+            })
+            .collect()
+    }
+}
+
+/// Gathers `indices` from every column of `batch` into a new batch.
+fn take_batch(batch: &RecordBatch, indices: &UInt64Array) -> Result<RecordBatch> {
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|c| arrow::compute::take(c, indices, None))
+        .collect::<ArrowResult<Vec<ArrayRef>>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    use super::*;
+
+    fn batch(values: &[i32]) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(values.to_vec()))])
+            .unwrap()
+    }
+
+    fn values(batch: &RecordBatch) -> Vec<i32> {
+        batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap()
+            .values()
+            .to_vec()
+    }
+
+    #[test]
+    fn counting_sort_is_stable_within_partitions() {
+        let partitioner = BatchPartitioner::try_new(
+            Partitioning::Hash(vec![], 2),
+            Time::default(),
+        )
+        .unwrap();
+
+        // rows alternate between partitions 0 and 1.
+        let input = batch(&[10, 11, 12, 13, 14]);
+        let partition_ids = [0u32, 1, 0, 1, 0];
+        let partitioned = partitioner.partition_by_ids(&input, &partition_ids).unwrap();
+
+        // each partition keeps the rows in their original relative order.
+        assert_eq!(partitioned.len(), 2);
+        assert_eq!(partitioned[0].0, 0);
+        assert_eq!(values(&partitioned[0].1), vec![10, 12, 14]);
+        assert_eq!(partitioned[1].0, 1);
+        assert_eq!(values(&partitioned[1].1), vec![11, 13]);
+    }
+
+    #[test]
+    fn round_robin_cursor_continues_across_batches() {
+        let partitioner = BatchPartitioner::try_new(
+            Partitioning::RoundRobinBatch(3),
+            Time::default(),
+        )
+        .unwrap();
+
+        // feeding one row at a time, the cursor must advance across calls so
+        // successive rows land on partitions 0, 1, 2, 0, ...
+        let assigned: Vec<usize> = (0..4)
+            .map(|i| {
+                let partitioned = partitioner.partition(&batch(&[i])).unwrap();
+                let slices: Vec<_> = partitioned.collect();
+                assert_eq!(slices.len(), 1);
+                slices[0].0
+            })
+            .collect();
+        assert_eq!(assigned, vec![0, 1, 2, 0]);
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local direct-I/O disk spill for shuffle repartitioners.
+//!
+//! When a repartitioner cannot keep its frozen partition batches in memory it
+//! spills the already-serialized blocks to a temporary file opened with
+//! `O_DIRECT`. Direct I/O keeps large spills out of the page cache so the OS
+//! does not evict hot Arrow buffers under memory pressure.
+//!
+//! Direct I/O requires block-aligned buffers, offsets and lengths, so every
+//! block is padded up to [`BLOCK_ALIGN`] on write; the true logical length is
+//! tracked per block so reads can truncate the padding back off. Spill files
+//! are removed on drop, including when a task is interrupted.
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::PathBuf;
+
+use datafusion::common::{DataFusionError, Result};
+
+/// Block-alignment boundary used for direct-I/O buffers, offsets and lengths.
+const BLOCK_ALIGN: usize = 4096;
+
+/// Location of a single spilled block within a [`SpillFile`].
+#[derive(Clone, Copy, Debug)]
+pub struct SpillBlock {
+    offset: u64,
+    /// true payload length, before padding up to [`BLOCK_ALIGN`].
+    logical_len: usize,
+}
+
+/// A temporary file holding serialized shuffle blocks, written with direct I/O.
+pub struct SpillFile {
+    file: File,
+    path: PathBuf,
+    /// next aligned write offset; always a multiple of [`BLOCK_ALIGN`].
+    write_offset: u64,
+}
+
+impl SpillFile {
+    /// Creates a new spill file under `dir`, opened for direct I/O.
+    ///
+    /// Not every filesystem supports `O_DIRECT` (notably tmpfs, which commonly
+    /// backs `/tmp`): such an open fails with `EINVAL`. Rather than failing the
+    /// spill — which would be strictly worse than flushing to rss — we fall back
+    /// to buffered I/O on that filesystem. The block-aligned, padded writes are
+    /// still valid over buffered I/O.
+    pub fn new(dir: impl Into<PathBuf>, name: &str) -> Result<Self> {
+        let path = dir.into().join(name);
+        let open = |direct: bool| {
+            let mut opts = OpenOptions::new();
+            opts.create_new(true).read(true).write(true);
+            if direct {
+                opts.custom_flags(libc::O_DIRECT);
+            }
+            opts.open(&path)
+        };
+        let file = match open(true) {
+            Ok(file) => file,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                open(false).map_err(DataFusionError::IoError)?
+            }
+            Err(e) => return Err(DataFusionError::IoError(e)),
+        };
+        Ok(Self {
+            file,
+            path,
+            write_offset: 0,
+        })
+    }
+
+    /// Appends a block, padding the write up to the alignment boundary. Returns
+    /// the location needed to read the exact logical bytes back.
+    pub fn append(&mut self, bytes: &[u8]) -> Result<SpillBlock> {
+        let padded_len = align_up(bytes.len());
+        let mut buf = AlignedBuf::new(padded_len);
+        buf[..bytes.len()].copy_from_slice(bytes);
+
+        let offset = self.write_offset;
+        self.file
+            .write_all_at(&buf, offset)
+            .map_err(|e| DataFusionError::IoError(e))?;
+        self.write_offset += padded_len as u64;
+
+        Ok(SpillBlock {
+            offset,
+            logical_len: bytes.len(),
+        })
+    }
+
+    /// Reads a previously appended block back, truncating the alignment padding.
+    pub fn read(&self, block: &SpillBlock) -> Result<Vec<u8>> {
+        let padded_len = align_up(block.logical_len);
+        let mut buf = AlignedBuf::new(padded_len);
+        self.file
+            .read_exact_at(&mut buf, block.offset)
+            .map_err(|e| DataFusionError::IoError(e))?;
+        Ok(buf[..block.logical_len].to_vec())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // best-effort cleanup; the file is a temp and must not outlive the task
+        // even when the task is interrupted mid-spill.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Rounds `len` up to the next multiple of [`BLOCK_ALIGN`].
+fn align_up(len: usize) -> usize {
+    (len + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1)
+}
+
+/// A heap buffer whose backing allocation is aligned to [`BLOCK_ALIGN`], as
+/// required by `O_DIRECT` reads and writes. The length is always a multiple of
+/// the alignment.
+struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        debug_assert_eq!(len % BLOCK_ALIGN, 0);
+        let layout = Layout::from_size_align(len, BLOCK_ALIGN).unwrap();
+        // SAFETY: layout has non-zero, aligned size; alloc_zeroed initializes it.
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr points to `len` initialized, aligned bytes.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: see Deref; the buffer is uniquely borrowed here.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len, BLOCK_ALIGN).unwrap();
+        // SAFETY: ptr/len/layout match the original allocation.
+        unsafe { dealloc(self.ptr, layout) };
+    }
+}
+
+/// Per-output-partition index of spilled blocks. Blocks are appended in the
+/// order they are frozen and drained back in partition order.
+pub struct PartitionSpill {
+    file: SpillFile,
+    blocks: Vec<Vec<SpillBlock>>,
+}
+
+impl PartitionSpill {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        name: &str,
+        num_output_partitions: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            file: SpillFile::new(dir, name)?,
+            blocks: vec![vec![]; num_output_partitions],
+        })
+    }
+
+    /// Spills one serialized block belonging to `partition_id`.
+    pub fn spill(&mut self, partition_id: usize, bytes: &[u8]) -> Result<()> {
+        let block = self.file.append(bytes)?;
+        self.blocks[partition_id].push(block);
+        Ok(())
+    }
+
+    /// Reads every spilled block for `partition_id` back in spill order.
+    pub fn drain_partition(&self, partition_id: usize) -> Result<Vec<Vec<u8>>> {
+        self.blocks[partition_id]
+            .iter()
+            .map(|block| self.file.read(block))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+    use super::*;
+
+    /// Unique file name so concurrent tests don't collide on `create_new`.
+    fn unique_name(prefix: &str) -> String {
+        static SEQ: AtomicUsize = AtomicUsize::new(0);
+        format!("{prefix}-{}-{}.tmp", std::process::id(), SEQ.fetch_add(1, Relaxed))
+    }
+
+    #[test]
+    fn spill_file_round_trips_and_truncates_padding() {
+        let mut file =
+            SpillFile::new(std::env::temp_dir(), &unique_name("spill-roundtrip")).unwrap();
+
+        // lengths that are not multiples of BLOCK_ALIGN, so writes get padded.
+        let first = vec![0xABu8; 100];
+        let second = vec![0xCDu8; BLOCK_ALIGN + 37];
+
+        let first_block = file.append(&first).unwrap();
+        let second_block = file.append(&second).unwrap();
+
+        // the second block starts on the next alignment boundary after the
+        // padded first block.
+        assert_eq!(first_block.offset, 0);
+        assert_eq!(second_block.offset, align_up(first.len()) as u64);
+
+        // reads truncate the alignment padding back to the logical length.
+        assert_eq!(file.read(&first_block).unwrap(), first);
+        assert_eq!(file.read(&second_block).unwrap(), second);
+    }
+
+    #[test]
+    fn partition_spill_drains_in_order_per_partition() {
+        let mut spill =
+            PartitionSpill::new(std::env::temp_dir(), &unique_name("spill-partitions"), 2)
+                .unwrap();
+
+        spill.spill(0, &[1, 2, 3]).unwrap();
+        spill.spill(1, &[9; 5000]).unwrap();
+        spill.spill(0, &[4, 5]).unwrap();
+
+        assert_eq!(spill.drain_partition(0).unwrap(), vec![vec![1, 2, 3], vec![4, 5]]);
+        assert_eq!(spill.drain_partition(1).unwrap(), vec![vec![9; 5000]]);
+    }
+}